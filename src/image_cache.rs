@@ -0,0 +1,586 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use glium;
+use image;
+
+use scratch_key;
+
+error_chain! {
+    foreign_links {
+        Io(::std::io::Error);
+        Image(::image::ImageError);
+        TextureCreation(::glium::texture::TextureCreationError);
+    }
+}
+
+/// How many neighbouring files (in each direction) the prefetch worker keeps
+/// header metadata warm for, so scrubbing quickly still finds dimensions
+/// ready the moment the cursor lands on them.
+const METADATA_LOOKAHEAD: usize = 8;
+
+/// Pixel dimensions, format and frame count resolved from a file's header
+/// alone, without decoding the full pixel buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub format: image::ImageFormat,
+    /// Number of frames the file contains; `1` for ordinary still images.
+    pub frame_count: u32,
+}
+
+/// Running totals for how a requested image was served: already resident in
+/// RAM, recovered from the on-disk scratch tier, or paid for with a full
+/// decode of the compressed source.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub ram_hits: u64,
+    pub disk_hits: u64,
+    pub decode_misses: u64,
+}
+
+#[derive(Clone)]
+struct DecodedImage {
+    pixels: Rc<Vec<u8>>,
+    width: u32,
+    height: u32,
+}
+
+impl DecodedImage {
+    fn byte_size(&self) -> isize {
+        self.pixels.len() as isize
+    }
+}
+
+struct DiskEntry {
+    scratch_path: PathBuf,
+    width: u32,
+    height: u32,
+    byte_size: isize,
+}
+
+/// A two-tier cache of decoded images backing directory-based slideshow
+/// navigation: a RAM tier sized from a byte budget (see
+/// `PlaybackManager::new`), and below it an on-disk tier that the RAM tier
+/// spills its evicted entries into instead of dropping them outright.
+///
+/// Looking up a path checks RAM, then disk, and only falls back to decoding
+/// the original (compressed) file if both miss - so scrubbing back and forth
+/// across a directory that doesn't fit in RAM stays fast as long as it fits
+/// on disk.
+pub struct ImageCache {
+    dir_entries: Vec<PathBuf>,
+    current_index: Option<usize>,
+    current_filename: OsString,
+
+    ram_capacity: isize,
+    ram_usage: isize,
+    ram_cache: HashMap<PathBuf, DecodedImage>,
+    /// Least-recently-used order; front is the next eviction candidate.
+    ram_order: VecDeque<PathBuf>,
+
+    disk_dir: PathBuf,
+    disk_capacity: isize,
+    disk_usage: isize,
+    disk_index: HashMap<PathBuf, DiskEntry>,
+    disk_order: VecDeque<PathBuf>,
+
+    metadata: HashMap<PathBuf, ImageMetadata>,
+    metadata_inflight: HashMap<PathBuf, ()>,
+    metadata_tx: SyncSender<(PathBuf, Option<ImageMetadata>)>,
+    metadata_rx: Receiver<(PathBuf, Option<ImageMetadata>)>,
+
+    stats: CacheStats,
+
+    thread_count: u32,
+}
+
+impl ImageCache {
+    pub fn new(ram_capacity: isize, thread_count: u32, disk_dir: PathBuf, disk_capacity: isize) -> Self {
+        if let Err(err) = fs::create_dir_all(&disk_dir) {
+            println!(
+                "Could not create image cache scratch directory {:?}: {}",
+                disk_dir, err
+            );
+        }
+
+        // Bounded only to avoid the prefetch worker racing arbitrarily far
+        // ahead of what `process_prefetched` can drain in a frame.
+        let (metadata_tx, metadata_rx) = mpsc::sync_channel(METADATA_LOOKAHEAD * 2);
+
+        ImageCache {
+            dir_entries: Vec::new(),
+            current_index: None,
+            current_filename: OsString::new(),
+
+            ram_capacity,
+            ram_usage: 0,
+            ram_cache: HashMap::new(),
+            ram_order: VecDeque::new(),
+
+            disk_dir,
+            disk_capacity,
+            disk_usage: 0,
+            disk_index: HashMap::new(),
+            disk_order: VecDeque::new(),
+
+            metadata: HashMap::new(),
+            metadata_inflight: HashMap::new(),
+            metadata_tx,
+            metadata_rx,
+
+            stats: CacheStats::default(),
+
+            thread_count,
+        }
+    }
+
+    pub fn current_filename<'a>(&'a self) -> &'a OsString {
+        &self.current_filename
+    }
+
+    fn set_current_index(&mut self, index: Option<usize>) {
+        self.current_index = index;
+        self.current_filename = index
+            .and_then(|index| self.dir_entries.get(index))
+            .and_then(|path| path.file_name())
+            .map(OsString::from)
+            .unwrap_or_default();
+    }
+
+    pub fn current_file_path(&self) -> PathBuf {
+        self.current_index
+            .and_then(|index| self.dir_entries.get(index))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn update_directory(&mut self) -> Result<()> {
+        let current_path = self.current_file_path();
+        self.update_directory_for(&current_path)
+    }
+
+    /// Drains whatever the background metadata-prefetch worker has resolved
+    /// since the last call, merging it into the persistent metadata map.
+    pub fn process_prefetched(&mut self, _display: &glium::Display) -> Result<()> {
+        while let Ok((path, metadata)) = self.metadata_rx.try_recv() {
+            self.metadata_inflight.remove(&path);
+            if let Some(metadata) = metadata {
+                self.metadata.insert(path, metadata);
+            }
+        }
+        Ok(())
+    }
+
+    /// Kicks off background header reads for the files around the current
+    /// position that we don't already have metadata for, so scrubbing
+    /// forward/back keeps dimensions ready just ahead of the cursor.
+    pub fn send_load_requests(&mut self) {
+        let index = match self.current_index {
+            Some(index) => index,
+            None => return,
+        };
+
+        let lookahead = METADATA_LOOKAHEAD.min(self.thread_count.max(1) as usize * 4);
+        let low = index.saturating_sub(lookahead);
+        let high = (index + lookahead).min(self.dir_entries.len().saturating_sub(1));
+
+        for candidate in &self.dir_entries[low..=high.max(low)] {
+            if self.metadata.contains_key(candidate) || self.metadata_inflight.contains_key(candidate) {
+                continue;
+            }
+            self.metadata_inflight.insert(candidate.clone(), ());
+
+            let path = candidate.clone();
+            let sender = self.metadata_tx.clone();
+            thread::spawn(move || {
+                let metadata = resolve_metadata(&path);
+                let _ = sender.send((path, metadata));
+            });
+        }
+    }
+
+    /// The currently-loaded file's resolved header metadata, if known. Eager
+    /// resolution is triggered by `prime_metadata`/`request_load` and by the
+    /// prefetch worker as it scrubs ahead; this simply reads whatever's
+    /// already in the map.
+    pub fn current_metadata(&self) -> Option<ImageMetadata> {
+        self.metadata.get(&self.current_file_path()).cloned()
+    }
+
+    /// Resolves `path`'s header metadata synchronously (cheap - no full
+    /// decode) and caches it immediately, rather than waiting for the
+    /// background prefetch worker to get to it.
+    pub fn prime_metadata(&mut self, path: &Path) {
+        if self.metadata.contains_key(path) {
+            return;
+        }
+        if let Some(metadata) = resolve_metadata(path) {
+            self.metadata.insert(path.to_path_buf(), metadata);
+        }
+    }
+
+    pub fn current_frame_count(&self) -> u32 {
+        self.current_metadata().map(|m| m.frame_count).unwrap_or(1)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn load_next(&mut self, display: &glium::Display) -> Result<(Rc<glium::texture::SrgbTexture2d>, OsString)> {
+        self.load_jump(display, 1)
+    }
+
+    pub fn load_prev(&mut self, display: &glium::Display) -> Result<(Rc<glium::texture::SrgbTexture2d>, OsString)> {
+        self.load_jump(display, -1)
+    }
+
+    pub fn load_jump(
+        &mut self,
+        display: &glium::Display,
+        jump_count: i32,
+    ) -> Result<(Rc<glium::texture::SrgbTexture2d>, OsString)> {
+        if self.dir_entries.is_empty() {
+            return Err("No images in directory".into());
+        }
+        let len = self.dir_entries.len() as i32;
+        let current = self.current_index.unwrap_or(0) as i32;
+        let next = ((current + jump_count) % len + len) % len;
+        self.set_current_index(Some(next as usize));
+
+        let path = self.dir_entries[next as usize].clone();
+        let texture = self.load_path(display, &path)?;
+        let filename = OsString::from(path.file_name().unwrap_or_default());
+        Ok((texture, filename))
+    }
+
+    pub fn load_specific(
+        &mut self,
+        display: &glium::Display,
+        path: &Path,
+    ) -> Result<Rc<glium::texture::SrgbTexture2d>> {
+        let texture = self.load_path(display, path)?;
+        let _ = self.update_directory_for(path);
+        Ok(texture)
+    }
+
+    /// Re-scans `path`'s parent directory and points `current_index` at
+    /// `path`, the same bookkeeping `update_directory` does for whatever
+    /// `current_file_path()` was already - used when `load_specific` jumps
+    /// to a file outside the previously tracked directory.
+    fn update_directory_for(&mut self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if dir.as_os_str().len() > 0 => dir.to_path_buf(),
+            _ => return Ok(()),
+        };
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|candidate| candidate.is_file())
+            .collect();
+        entries.sort();
+
+        let index = entries.iter().position(|entry| entry == path);
+        self.dir_entries = entries;
+        self.set_current_index(index);
+        Ok(())
+    }
+
+    fn load_path(&mut self, display: &glium::Display, path: &Path) -> Result<Rc<glium::texture::SrgbTexture2d>> {
+        let decoded = self.obtain_image(path)?;
+        let raw = glium::texture::RawImage2d::from_raw_rgba_reversed(
+            &decoded.pixels,
+            (decoded.width, decoded.height),
+        );
+        let texture = glium::texture::SrgbTexture2d::new(display, raw)?;
+        Ok(Rc::new(texture))
+    }
+
+    /// RAM tier, then disk tier, then a full decode - in that order, each
+    /// promoting the result back into the RAM tier (at the cost of possibly
+    /// spilling something else out to disk).
+    fn obtain_image(&mut self, path: &Path) -> Result<DecodedImage> {
+        if let Some(image) = self.take_ram(path) {
+            self.stats.ram_hits += 1;
+            self.insert_ram(path.to_path_buf(), image.clone());
+            return Ok(image);
+        }
+
+        if let Some(image) = self.take_disk(path)? {
+            self.stats.disk_hits += 1;
+            self.insert_ram(path.to_path_buf(), image.clone());
+            return Ok(image);
+        }
+
+        self.stats.decode_misses += 1;
+        let image = decode_full(path)?;
+        self.insert_ram(path.to_path_buf(), image.clone());
+        Ok(image)
+    }
+
+    fn take_ram(&mut self, path: &Path) -> Option<DecodedImage> {
+        let image = self.ram_cache.get(path).cloned()?;
+        self.ram_order.retain(|entry| entry != path);
+        Some(image)
+    }
+
+    fn insert_ram(&mut self, path: PathBuf, image: DecodedImage) {
+        if let Some(old) = self.ram_cache.remove(&path) {
+            self.ram_usage -= old.byte_size();
+            self.ram_order.retain(|entry| entry != &path);
+        }
+
+        self.ram_usage += image.byte_size();
+        self.ram_order.push_back(path.clone());
+        self.ram_cache.insert(path, image);
+
+        while self.ram_usage > self.ram_capacity {
+            let evicted_path = match self.ram_order.pop_front() {
+                Some(path) => path,
+                None => break,
+            };
+            if let Some(evicted) = self.ram_cache.remove(&evicted_path) {
+                self.ram_usage -= evicted.byte_size();
+                self.spill_to_disk(&evicted_path, &evicted);
+            }
+        }
+    }
+
+    fn spill_to_disk(&mut self, path: &Path, image: &DecodedImage) {
+        let scratch_path = scratch_key::scratch_path(&self.disk_dir, path, "rawpixels");
+        if File::create(&scratch_path)
+            .and_then(|mut file| file.write_all(&image.pixels))
+            .is_err()
+        {
+            // Best-effort: if we can't write the scratch entry we just lose
+            // the chance to skip a future re-decode, nothing more.
+            return;
+        }
+
+        let byte_size = image.byte_size();
+        if let Some(old) = self.disk_index.remove(path) {
+            self.disk_usage -= old.byte_size;
+            self.disk_order.retain(|entry| entry != path);
+        }
+
+        self.disk_usage += byte_size;
+        self.disk_order.push_back(path.to_path_buf());
+        self.disk_index.insert(
+            path.to_path_buf(),
+            DiskEntry {
+                scratch_path,
+                width: image.width,
+                height: image.height,
+                byte_size,
+            },
+        );
+
+        while self.disk_usage > self.disk_capacity {
+            let evicted_path = match self.disk_order.pop_front() {
+                Some(path) => path,
+                None => break,
+            };
+            if let Some(evicted) = self.disk_index.remove(&evicted_path) {
+                self.disk_usage -= evicted.byte_size;
+                let _ = fs::remove_file(&evicted.scratch_path);
+            }
+        }
+    }
+
+    fn take_disk(&mut self, path: &Path) -> Result<Option<DecodedImage>> {
+        let entry = match self.disk_index.get(path) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let mut pixels = vec![0u8; entry.byte_size as usize];
+        let mut file = match File::open(&entry.scratch_path) {
+            Ok(file) => file,
+            // The scratch file is gone from under us (e.g. manually
+            // cleared); fall through to a full decode instead of failing.
+            Err(_) => {
+                self.evict_stale_disk_entry(path);
+                return Ok(None);
+            }
+        };
+
+        // A truncated/corrupted scratch entry is just as recoverable as a
+        // missing one - fall through to a full decode rather than
+        // propagating the error, which would otherwise turn a stale cache
+        // entry into a hard, user-visible load failure.
+        if file.read_exact(&mut pixels).is_err() {
+            self.evict_stale_disk_entry(path);
+            return Ok(None);
+        }
+
+        self.disk_order.retain(|entry| entry != path);
+        self.disk_order.push_back(path.to_path_buf());
+
+        Ok(Some(DecodedImage {
+            pixels: Rc::new(pixels),
+            width: entry.width,
+            height: entry.height,
+        }))
+    }
+
+    /// Drops a disk-tier entry that turned out to be unusable (its scratch
+    /// file is missing or unreadable), keeping `disk_usage`/`disk_order` in
+    /// sync with `disk_index`.
+    fn evict_stale_disk_entry(&mut self, path: &Path) {
+        if let Some(stale) = self.disk_index.remove(path) {
+            self.disk_usage -= stale.byte_size;
+        }
+        self.disk_order.retain(|entry| entry != path);
+    }
+}
+
+fn decode_full(path: &Path) -> Result<DecodedImage> {
+    let image = image::open(path)?.to_rgba8();
+    let (width, height) = (image.width(), image.height());
+    Ok(DecodedImage {
+        pixels: Rc::new(image.into_raw()),
+        width,
+        height,
+    })
+}
+
+fn resolve_metadata(path: &Path) -> Option<ImageMetadata> {
+    let format = image::ImageFormat::from_path(path).ok()?;
+    let (width, height) = image::image_dimensions(path).ok()?;
+    let frame_count = count_frames(path, format).unwrap_or(1);
+    Some(ImageMetadata {
+        width,
+        height,
+        format,
+        frame_count,
+    })
+}
+
+/// Counts frames by walking the file's block/chunk structure, never
+/// decoding a single pixel. Formats we don't have a cheap counter for just
+/// report `1` - they're treated as ordinary still images.
+fn count_frames(path: &Path, format: image::ImageFormat) -> Result<u32> {
+    match format {
+        image::ImageFormat::Gif => count_gif_frames(path),
+        image::ImageFormat::Png => count_apng_frames(path),
+        _ => Ok(1),
+    }
+}
+
+/// Walks GIF blocks (extension introducers and image descriptors), seeking
+/// over sub-block and color-table data by its length prefix rather than
+/// reading it, to count frames without touching pixel data or the rest of
+/// the file.
+fn count_gif_frames(path: &Path) -> Result<u32> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut header = [0u8; 13];
+    if reader.read_exact(&mut header).is_err() || &header[0..3] != b"GIF" {
+        return Ok(1);
+    }
+
+    let screen_flags = header[10];
+    if screen_flags & 0x80 != 0 {
+        let table_size = 3 * (2i64 << (screen_flags & 0x07));
+        reader.seek(SeekFrom::Current(table_size))?;
+    }
+
+    let mut frames = 0u32;
+    loop {
+        let mut tag = [0u8; 1];
+        if reader.read_exact(&mut tag).is_err() {
+            break;
+        }
+        match tag[0] {
+            0x21 => {
+                // Extension block: skip the label byte, then its sub-blocks.
+                reader.seek(SeekFrom::Current(1))?;
+                skip_sub_blocks(&mut reader)?;
+            }
+            0x2C => {
+                // Image descriptor.
+                frames += 1;
+                let mut descriptor = [0u8; 9];
+                if reader.read_exact(&mut descriptor).is_err() {
+                    break;
+                }
+                let local_flags = descriptor[8];
+                if local_flags & 0x80 != 0 {
+                    let table_size = 3 * (2i64 << (local_flags & 0x07));
+                    reader.seek(SeekFrom::Current(table_size))?;
+                }
+                reader.seek(SeekFrom::Current(1))?; // LZW minimum code size
+                skip_sub_blocks(&mut reader)?;
+            }
+            0x3B => break, // trailer
+            _ => break,
+        }
+    }
+
+    Ok(frames.max(1))
+}
+
+fn skip_sub_blocks(reader: &mut BufReader<File>) -> Result<()> {
+    loop {
+        let mut len = [0u8; 1];
+        if reader.read_exact(&mut len).is_err() || len[0] == 0 {
+            break;
+        }
+        reader.seek(SeekFrom::Current(len[0] as i64))?;
+    }
+    Ok(())
+}
+
+/// Looks for PNG's `acTL` chunk (which, if present, makes the file an APNG)
+/// and reads its frame count directly out of the chunk body - the same
+/// constant-time header walk PNG decoders use to detect APNG before
+/// touching `IDAT`. Only the 8-byte signature, each chunk's 8-byte
+/// length+type header and the 4-byte frame count in `acTL` are actually
+/// read; everything else is skipped with a seek, so this never pulls more than a handful of bytes off disk.
+fn count_apng_frames(path: &Path) -> Result<u32> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut signature = [0u8; 8];
+    if reader.read_exact(&mut signature).is_err() {
+        return Ok(1);
+    }
+
+    loop {
+        let mut chunk_header = [0u8; 8];
+        if reader.read_exact(&mut chunk_header).is_err() {
+            break;
+        }
+        let length = u32::from_be_bytes([
+            chunk_header[0],
+            chunk_header[1],
+            chunk_header[2],
+            chunk_header[3],
+        ]);
+        let chunk_type = &chunk_header[4..8];
+
+        if chunk_type == b"acTL" {
+            let mut frame_count = [0u8; 4];
+            if reader.read_exact(&mut frame_count).is_err() {
+                break;
+            }
+            return Ok(u32::from_be_bytes(frame_count).max(1));
+        }
+        if chunk_type == b"IDAT" {
+            // acTL must appear before the first IDAT; if we hit pixel data
+            // first this isn't an APNG.
+            break;
+        }
+        reader.seek(SeekFrom::Current(length as i64 + 4))?; // remaining data + CRC
+    }
+
+    Ok(1)
+}
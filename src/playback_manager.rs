@@ -1,10 +1,14 @@
 
+mod animation;
+mod sleep;
+
+use std::env;
 use std::mem;
 use std::ffi::OsString;
 use std::io::Write;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use sys_info;
 
@@ -15,6 +19,44 @@ use window::Window;
 use image_cache;
 use image_cache::ImageCache;
 
+use self::animation::AnimationPlayer;
+
+/// Slideshow stepping rate used while no finer-grained per-frame delay (as
+/// in-file animations provide) applies.
+fn frame_delta() -> Duration {
+    Duration::from_nanos(1_000_000_000 / 25)
+}
+
+/// Bounds on the playback speed accepted by `set_speed`/`start_playback`.
+/// Anything non-finite, non-positive, or large enough to round
+/// `scaled_frame_delta`'s result down to zero would leave `next_frame_deadline`
+/// unable to advance past `now`, spinning `update_image`'s catch-up loop
+/// forever - so speed is clamped to this range before it's ever used.
+const MIN_PLAYBACK_SPEED: f64 = 0.05;
+const MAX_PLAYBACK_SPEED: f64 = 20.0;
+
+fn clamp_speed(speed: f64) -> f64 {
+    if !speed.is_finite() {
+        return 1.0;
+    }
+    speed.max(MIN_PLAYBACK_SPEED).min(MAX_PLAYBACK_SPEED)
+}
+
+/// `frame_delta()` scaled by the inverse of `speed`: double speed halves the
+/// interval between steps, half speed doubles it.
+fn scaled_frame_delta(speed: f64) -> Duration {
+    let speed = clamp_speed(speed);
+    let base = frame_delta();
+    let base_nanos = base.as_secs() * 1_000_000_000 + base.subsec_nanos() as u64;
+    Duration::from_nanos((base_nanos as f64 / speed) as u64)
+}
+
+/// Default on-disk budget for the second-tier, decoded-pixel cache. Unlike
+/// the RAM tier (sized from total system memory in `PlaybackManager::new`),
+/// disk is cheap and plentiful, so this is just a generous fixed ceiling
+/// rather than a fraction of anything.
+const DEFAULT_DISK_CACHE_CAPACITY: isize = 2_000_000_000;
+
 #[derive(PartialEq)]
 pub enum LoadRequest {
     None,
@@ -28,7 +70,13 @@ pub enum LoadRequest {
 pub enum PlaybackState {
     Paused,
     Forward,
-    //Backward,
+    Backward,
+    /// Playing back the frames of the single currently-loaded file (an
+    /// animated GIF or APNG), driven by a background decoder thread rather
+    /// than by stepping through the directory. Animated WebP isn't decoded
+    /// frame-by-frame (see `animation::AnimationPlayer`); it's presented as
+    /// its first frame like any other still image.
+    PresentAnimation,
 }
 
 pub struct PlaybackManager {
@@ -36,14 +84,28 @@ pub struct PlaybackManager {
 
     image_cache: ImageCache,
 
-    playback_start_time: Instant,
-    frame_count_since_playback_start: u64,
+    /// The target presentation time of the next slideshow frame. Advanced by
+    /// the current frame's delay (not by wall-clock elapsed time) after every
+    /// step, so the schedule can't accumulate drift even when a decode comes
+    /// in late.
+    next_frame_deadline: Instant,
+
+    /// Speed factor for slideshow steps; `frame_delta()` is divided by it when
+    /// scheduling. 1.0 is normal speed, 2.0 is double speed, 0.5 is half speed.
+    playback_speed: f64,
 
     load_request: LoadRequest,
 
-    should_sleep: bool,
+    should_sleep: Option<Duration>,
 
     image_texture: Option<Rc<glium::texture::SrgbTexture2d>>,
+
+    /// Set whenever the currently loaded file turns out to be a multi-frame
+    /// animation; `None` for plain still images.
+    animation_player: Option<AnimationPlayer>,
+    /// When the next buffered animation frame should be uploaded to
+    /// `image_texture`.
+    next_animation_frame_time: Instant,
 }
 
 
@@ -66,16 +128,26 @@ impl PlaybackManager {
             _ => 4,
         };
 
+        let scratch_dir = env::temp_dir().join("emulsion-cache");
+
         let resulting_window = PlaybackManager {
-            image_cache: ImageCache::new(cache_capaxity, thread_count),
+            image_cache: ImageCache::new(
+                cache_capaxity,
+                thread_count,
+                scratch_dir,
+                DEFAULT_DISK_CACHE_CAPACITY,
+            ),
 
             playback_state: PlaybackState::Paused,
-            playback_start_time: Instant::now(),
-            frame_count_since_playback_start: 0,
+            next_frame_deadline: Instant::now(),
+            playback_speed: 1.0,
             load_request: LoadRequest::None,
-            should_sleep: true,
+            should_sleep: None,
 
-            image_texture: None
+            image_texture: None,
+
+            animation_player: None,
+            next_animation_frame_time: Instant::now(),
         };
 
         resulting_window
@@ -86,12 +158,43 @@ impl PlaybackManager {
     }
 
     pub fn start_playback_forward(&mut self) {
-        self.playback_start_time = Instant::now();
-        self.frame_count_since_playback_start = 0;
-        self.playback_state = PlaybackState::Forward;
+        self.start_playback(PlaybackState::Forward, self.playback_speed);
+    }
+
+    /// Starts (or restarts) slideshow playback in `direction` at `speed`
+    /// (1.0 is normal speed; use values above 1.0 to step through the
+    /// directory faster and below 1.0 to slow it down).
+    ///
+    /// `direction` must be `Forward` or `Backward`; anything else panics,
+    /// since pausing or in-file animation go through their own entry points.
+    pub fn start_playback(&mut self, direction: PlaybackState, speed: f64) {
+        match direction {
+            PlaybackState::Forward | PlaybackState::Backward => (),
+            PlaybackState::Paused | PlaybackState::PresentAnimation => {
+                panic!("start_playback only accepts Forward or Backward")
+            }
+        }
+        // Leaving PresentAnimation: drop the decoder thread rather than
+        // leaving it running in the background until the directory slideshow
+        // happens to load the same file again.
+        self.animation_player = None;
+        self.playback_speed = clamp_speed(speed);
+        self.next_frame_deadline = Instant::now() + scaled_frame_delta(self.playback_speed);
+        self.playback_state = direction;
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.playback_speed = clamp_speed(speed);
+    }
+
+    pub fn playback_speed(&self) -> f64 {
+        self.playback_speed
     }
 
     pub fn pause_playback(&mut self) {
+        // Same reasoning as start_playback: don't leave a PresentAnimation
+        // decoder thread running once we're no longer presenting its frames.
+        self.animation_player = None;
         self.playback_state = PlaybackState::Paused;
     }
 
@@ -107,14 +210,63 @@ impl PlaybackManager {
         self.image_cache.update_directory()
     }
 
-    pub fn should_sleep(&self) -> bool {
+    /// RAM-hit / disk-hit / decode-miss counters for the two-tier image
+    /// cache, for diagnostics/UI display.
+    pub fn cache_stats(&self) -> image_cache::CacheStats {
+        self.image_cache.cache_stats()
+    }
+
+    /// How long the outer event loop should park before calling back into
+    /// `update_image`, or `None` if it should simply block until the next
+    /// user/window event (nothing is scheduled).
+    pub fn should_sleep(&self) -> Option<Duration> {
         self.should_sleep
     }
 
+    /// The absolute instant the next scheduled frame (slideshow step or
+    /// in-file animation frame) is due to be presented.
+    pub fn next_deadline(&self) -> Instant {
+        match self.playback_state {
+            PlaybackState::PresentAnimation => self.next_animation_frame_time,
+            _ => self.next_frame_deadline,
+        }
+    }
+
+    /// Parks the calling thread precisely until `next_deadline()`, using a
+    /// high-resolution absolute sleep rather than the coarser duration
+    /// `should_sleep()` hints at. Does not look at `playback_state()`: while
+    /// paused it still parks until the last scheduled deadline (returning at
+    /// once if that has passed), so callers should check `playback_state()`
+    /// first.
+    pub fn park_until_next_deadline(&self) {
+        sleep::park_until(self.next_deadline());
+    }
+
     pub fn request_load(&mut self, request: LoadRequest) {
+        // Kick off metadata resolution (dimensions/format/frame-count from the
+        // file header, no full decode) the moment we know which file we're
+        // headed for, rather than waiting for the pixel load to finish. The
+        // directory-relative requests (`LoadNext`/`LoadPrevious`/`Jump`) go
+        // through the prefetch worker's own metadata lookups instead, since
+        // we don't know which path they resolve to until the cache does.
+        if let LoadRequest::LoadSpecific(ref path) = request {
+            self.image_cache.prime_metadata(path);
+        }
         self.load_request = request;
     }
 
+    /// The current file's pixel dimensions, format and frame count, if known.
+    ///
+    /// This resolves from the file header alone (populated eagerly by
+    /// `request_load` and by the prefetch worker as it scrubs ahead), without
+    /// a full decode. It describes the file the image cache currently points
+    /// at: a pending `LoadRequest` does not change that, so until
+    /// `update_image` has loaded the requested file this still reports the
+    /// previously loaded one.
+    pub fn current_metadata(&self) -> Option<image_cache::ImageMetadata> {
+        self.image_cache.current_metadata()
+    }
+
     pub fn load_request<'a>(&'a self) -> &'a LoadRequest {
         &self.load_request
     }
@@ -125,7 +277,7 @@ impl PlaybackManager {
 
 
     pub fn update_image(&mut self, window: &mut Window) {
-        self.should_sleep = true;
+        self.should_sleep = Some(frame_delta());
 
         // The reason why I reset the load request in such a convoluted way is that
         // it has to guarantee that self.load_request will be reset even if I return from this
@@ -133,39 +285,40 @@ impl PlaybackManager {
         let mut load_request = LoadRequest::None;
         mem::swap(&mut self.load_request, &mut load_request);
 
-        let framerate = 25.0;
-        const NANOS_PER_SEC: u64 = 1000_000_000;
-        let frame_delta_time_nanos = (NANOS_PER_SEC as f64 / framerate) as u64;
-
         if self.playback_state == PlaybackState::Paused {
             self.image_cache.process_prefetched(window.display()).unwrap();
             self.image_cache.send_load_requests();
+            self.should_sleep = None;
+        } else if self.playback_state == PlaybackState::PresentAnimation {
+            self.update_animation_frame(window);
         } else if load_request == LoadRequest::None {
-            let elapsed = self.playback_start_time.elapsed();
-            let elapsed_nanos =
-                elapsed.as_secs() * NANOS_PER_SEC + elapsed.subsec_nanos() as u64;
-            let frame_step =
-                (elapsed_nanos / frame_delta_time_nanos) - self.frame_count_since_playback_start;
+            let now = Instant::now();
+            // Count off however many deadlines have already elapsed, advancing
+            // by the frame's own delay each time rather than re-deriving the
+            // count from wall-clock elapsed time. This is what keeps the
+            // schedule from drifting when a decode or a GC pause makes one
+            // frame land late: we don't "lose" that lateness into the next
+            // delta, we just immediately owe however many frames we missed.
+            let step_delta = scaled_frame_delta(self.playback_speed);
+            let mut frame_step = 0i32;
+            while now >= self.next_frame_deadline {
+                self.next_frame_deadline += step_delta;
+                frame_step += 1;
+            }
+
             if frame_step > 0 {
                 load_request = match self.playback_state {
-                    PlaybackState::Forward => LoadRequest::Jump(frame_step as i32),
-                    //PlaybackState::Backward => LoadRequest::Jump(-(frame_step as i32)),
-                    PlaybackState::Paused => unreachable!(),
+                    PlaybackState::Forward => LoadRequest::Jump(frame_step),
+                    PlaybackState::Backward => LoadRequest::Jump(-frame_step),
+                    PlaybackState::Paused | PlaybackState::PresentAnimation => unreachable!(),
                 };
-                self.frame_count_since_playback_start += frame_step;
             } else {
                 self.image_cache.process_prefetched(window.display()).unwrap();
-
-                let nanos_since_last = elapsed_nanos % frame_delta_time_nanos;
-                const BUISY_WAIT_TRESHOLD: f32 = 0.8;
-                if nanos_since_last
-                    > (frame_delta_time_nanos as f32 * BUISY_WAIT_TRESHOLD) as u64
-                {
-                    // Just buisy wait if we are getting very close to the next frame swap
-                    self.should_sleep = false;
-                } else {
-                    self.image_cache.send_load_requests();
-                }
+                self.image_cache.send_load_requests();
+                self.should_sleep = Some(
+                    self.next_frame_deadline
+                        .saturating_duration_since(now),
+                );
             }
         }
 
@@ -192,6 +345,17 @@ impl PlaybackManager {
             match result {
                 Ok((texture, filename)) => {
                     self.image_texture = Some(texture);
+
+                    // A freshly loaded file replaces whatever animation (if any)
+                    // was previously playing back.
+                    self.animation_player = None;
+                    if self.image_cache.current_frame_count() > 1 {
+                        self.animation_player =
+                            Some(AnimationPlayer::start(self.image_cache.current_file_path()));
+                        self.next_animation_frame_time = Instant::now();
+                        self.playback_state = PlaybackState::PresentAnimation;
+                    }
+
                     // FIXME the program hangs when the title is set during a resize
                     // this is due to the way glutin/winit is architected.
                     // An issu already exists in winit proposing to redesign
@@ -219,7 +383,115 @@ impl PlaybackManager {
                 }
             }
 
-            self.should_sleep = false;
+            self.should_sleep = Some(Duration::from_secs(0));
+        }
+    }
+
+    /// Pulls the next buffered frame from `self.animation_player` (if it's due)
+    /// and uploads it to `self.image_texture`. Each frame schedules its own
+    /// successor using the per-frame delay reported by the decoder, rather
+    /// than a fixed framerate.
+    fn update_animation_frame(&mut self, window: &mut Window) {
+        self.image_cache.process_prefetched(window.display()).unwrap();
+
+        let now = Instant::now();
+        if now < self.next_animation_frame_time {
+            // Not due yet; park until it is.
+            self.should_sleep = Some(self.next_animation_frame_time.saturating_duration_since(now));
+            return;
+        }
+
+        let frame = match self.animation_player.as_mut() {
+            Some(player) => player.try_recv_frame(),
+            None => return,
+        };
+
+        match frame {
+            Some(Ok(frame)) => {
+                let raw_image = glium::texture::RawImage2d::from_raw_rgba_reversed(
+                    &frame.pixels,
+                    (frame.width, frame.height),
+                );
+                match glium::texture::SrgbTexture2d::new(window.display(), raw_image) {
+                    Ok(texture) => self.image_texture = Some(Rc::new(texture)),
+                    Err(err) => {
+                        writeln!(
+                            ::std::io::stderr(),
+                            "Could not upload animation frame: {}",
+                            err
+                        ).expect("Error writing to stderr");
+                    }
+                }
+                self.next_animation_frame_time =
+                    Instant::now() + Duration::from_nanos(frame.delay_nanos);
+                self.should_sleep = Some(
+                    self.next_animation_frame_time
+                        .saturating_duration_since(Instant::now()),
+                );
+            }
+            Some(Err(err)) => {
+                writeln!(
+                    ::std::io::stderr(),
+                    "Animation decoder stopped: {}",
+                    err
+                ).expect("Error writing to stderr");
+                self.animation_player = None;
+                self.playback_state = PlaybackState::Paused;
+                self.should_sleep = None;
+            }
+            None => {
+                // Decoder hasn't produced the next frame yet; check back
+                // shortly rather than stalling for the full frame delay.
+                self.should_sleep = Some(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_frame_delta_at_normal_speed_matches_base_rate() {
+        assert_eq!(scaled_frame_delta(1.0), frame_delta());
+    }
+
+    #[test]
+    fn scaled_frame_delta_halves_interval_at_double_speed() {
+        let base_nanos = frame_delta().subsec_nanos() as u64 + frame_delta().as_secs() * 1_000_000_000;
+        assert_eq!(scaled_frame_delta(2.0), Duration::from_nanos(base_nanos / 2));
+    }
+
+    #[test]
+    fn scaled_frame_delta_doubles_interval_at_half_speed() {
+        let base_nanos = frame_delta().subsec_nanos() as u64 + frame_delta().as_secs() * 1_000_000_000;
+        assert_eq!(scaled_frame_delta(0.5), Duration::from_nanos(base_nanos * 2));
+    }
+
+    #[test]
+    fn scaled_frame_delta_never_reaches_zero_for_degenerate_speeds() {
+        for speed in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e30] {
+            assert!(
+                scaled_frame_delta(speed) > Duration::from_nanos(0),
+                "speed {} produced a zero step delta",
+                speed
+            );
         }
     }
+
+    #[test]
+    fn clamp_speed_passes_through_in_range_values() {
+        assert_eq!(clamp_speed(1.0), 1.0);
+        assert_eq!(clamp_speed(3.0), 3.0);
+    }
+
+    #[test]
+    fn clamp_speed_clamps_out_of_range_and_non_finite_values() {
+        assert_eq!(clamp_speed(0.0), MIN_PLAYBACK_SPEED);
+        assert_eq!(clamp_speed(-5.0), MIN_PLAYBACK_SPEED);
+        assert_eq!(clamp_speed(1e30), MAX_PLAYBACK_SPEED);
+        assert_eq!(clamp_speed(f64::NAN), 1.0);
+        assert_eq!(clamp_speed(f64::INFINITY), 1.0);
+    }
 }
@@ -0,0 +1,306 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use image;
+use image::AnimationDecoder;
+
+use image_cache;
+use scratch_key;
+
+/// Number of decoded frames the main loop is willing to keep live in memory at
+/// once while an in-file animation is looping. The scratch file on disk is
+/// allowed to hold every frame of the animation; this is just the live,
+/// triple(-ish)-buffered portion that actually sits in RAM at a time.
+const LIVE_FRAME_BUDGET: usize = 4;
+
+/// How often a blocked `send_or_stop` re-checks `stop` while the channel is
+/// full. Playback only drains one frame per scheduled frame delay, so the
+/// channel spends most of its time full; polling at this interval trades a
+/// little latency in noticing `stop` for not busy-spinning in the meantime.
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A single decoded, uncompressed frame of an in-file animation, ready to be
+/// uploaded to a texture.
+pub struct AnimationFrame {
+    pub pixels: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub delay_nanos: u64,
+}
+
+/// Where in the scratch file a single frame's raw pixels live, plus the bits of
+/// metadata we need to re-read them without going back to the compressed source.
+struct ScratchFrame {
+    offset: u64,
+    width: u32,
+    height: u32,
+    delay_nanos: u64,
+}
+
+/// Drives a background decoder thread that streams the frames of a single
+/// animated image (GIF / APNG) to the main loop over a bounded channel.
+/// Animated WebP isn't decoded frame-by-frame here: `image_cache`'s metadata
+/// resolution only reports a `frame_count` above 1 for GIF and APNG, so a
+/// WebP file is always presented as its already-loaded first frame instead of
+/// ever reaching an `AnimationPlayer`.
+///
+/// On the first pass through the animation every frame is decoded from the
+/// compressed source and appended to a scratch file on disk. Once the loop
+/// point is reached, rewinding back to frame 0 just re-reads that scratch file
+/// instead of paying the decode cost again.
+pub struct AnimationPlayer {
+    frame_receiver: Receiver<image_cache::Result<AnimationFrame>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl AnimationPlayer {
+    /// Spawns the decoder thread for `path` and starts streaming frames.
+    pub fn start(path: PathBuf) -> AnimationPlayer {
+        // Bounded so the decoder thread can't race ahead of the GPU upload and
+        // blow past our live-memory budget; it will simply block until the
+        // main loop has consumed a frame.
+        let (sender, receiver) = mpsc::sync_channel(LIVE_FRAME_BUDGET);
+        let scratch_path = scratch_key::scratch_path(&temp_cache_dir(), &path, "rawframes");
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+
+        let worker = thread::spawn(move || {
+            decode_loop(&path, &scratch_path, &sender, &worker_stop);
+        });
+
+        AnimationPlayer {
+            frame_receiver: receiver,
+            stop,
+            worker: Some(worker),
+        }
+    }
+
+    /// Returns the next decoded frame if the decoder thread has produced one,
+    /// without blocking the main loop.
+    pub fn try_recv_frame(&mut self) -> Option<image_cache::Result<AnimationFrame>> {
+        match self.frame_receiver.try_recv() {
+            Ok(frame) => Some(frame),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+impl Drop for AnimationPlayer {
+    /// Signals the decoder thread to stop and waits for it to actually exit
+    /// before returning. Without this, replacing one `AnimationPlayer` with
+    /// another for the *same* file races: the old thread only notices it
+    /// should stop the next time `sender.send` fails, and in the meantime it
+    /// may delete the shared scratch file that the replacement is still
+    /// writing to. Joining here guarantees the old thread (and its own
+    /// scratch file cleanup) is fully done before a new one can start.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn temp_cache_dir() -> PathBuf {
+    ::std::env::temp_dir().join("emulsion-cache")
+}
+
+/// Delivers `frame` without ever blocking on a full channel, so `Drop` can
+/// always wake this thread up by setting `stop` - a bare blocking `send`
+/// would ignore `stop` entirely and could never be woken, since the
+/// receiving end isn't released until after `Drop::drop` returns. Retries
+/// against a full channel until it's consumed, the receiver disconnects, or
+/// `stop` is set.
+///
+/// Returns `true` if decoding should stop (the receiver is gone or `stop`
+/// was set before the frame could be delivered).
+fn send_or_stop(
+    sender: &SyncSender<image_cache::Result<AnimationFrame>>,
+    stop: &AtomicBool,
+    mut frame: image_cache::Result<AnimationFrame>,
+) -> bool {
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return true;
+        }
+        match sender.try_send(frame) {
+            Ok(()) => return false,
+            Err(TrySendError::Full(returned)) => {
+                frame = returned;
+                thread::sleep(SEND_RETRY_INTERVAL);
+            }
+            Err(TrySendError::Disconnected(_)) => return true,
+        }
+    }
+}
+
+/// Decodes `path` frame by frame, forwarding each one to `sender`. The first
+/// time through the animation, every decoded frame is also appended to
+/// `scratch_path`; subsequent loops read the already-decoded pixels back from
+/// that scratch file instead of decoding the compressed source again.
+///
+/// `stop` is checked before every send; once it's set (the `AnimationPlayer`
+/// was dropped, e.g. a different file was loaded) this returns promptly
+/// instead of waiting for a send to fail, since the receiving end isn't
+/// actually disconnected until the whole `AnimationPlayer` is dropped.
+fn decode_loop(
+    path: &Path,
+    scratch_path: &Path,
+    sender: &SyncSender<image_cache::Result<AnimationFrame>>,
+    stop: &AtomicBool,
+) {
+    let index = match decode_first_pass(path, scratch_path, sender, stop) {
+        Ok(index) => index,
+        Err(err) => {
+            send_or_stop(sender, stop, Err(err));
+            return;
+        }
+    };
+
+    if index.is_empty() {
+        return;
+    }
+
+    // Every subsequent loop is just a cheap, uncompressed read of the scratch
+    // file rather than a re-decode of the compressed source.
+    loop {
+        let mut scratch = match File::open(scratch_path) {
+            Ok(file) => file,
+            Err(err) => {
+                send_or_stop(sender, stop, Err(err.into()));
+                return;
+            }
+        };
+
+        for frame in &index {
+            if stop.load(Ordering::Relaxed) {
+                let _ = fs::remove_file(scratch_path);
+                return;
+            }
+
+            let mut pixels = vec![0u8; (frame.width * frame.height * 4) as usize];
+            if let Err(err) = scratch
+                .seek(SeekFrom::Start(frame.offset))
+                .and_then(|_| scratch.read_exact(&mut pixels))
+            {
+                send_or_stop(sender, stop, Err(err.into()));
+                return;
+            }
+
+            let should_stop = send_or_stop(
+                sender,
+                stop,
+                Ok(AnimationFrame {
+                    pixels,
+                    width: frame.width,
+                    height: frame.height,
+                    delay_nanos: frame.delay_nanos,
+                }),
+            );
+            if should_stop {
+                // Either nobody is listening any more, or playback moved on.
+                let _ = fs::remove_file(scratch_path);
+                return;
+            }
+        }
+    }
+}
+
+/// Decodes `path` once, forwarding frames to `sender` as they come in and
+/// appending each one's raw pixels to `scratch_path`. Returns the scratch
+/// index built up along the way so later loops can skip straight to re-reading
+/// it.
+///
+/// GIF and APNG are decoded frame-by-frame via `image`'s `AnimationDecoder`;
+/// any other format never reaches here (see `AnimationPlayer`'s doc comment).
+fn decode_first_pass(
+    path: &Path,
+    scratch_path: &Path,
+    sender: &SyncSender<image_cache::Result<AnimationFrame>>,
+    stop: &AtomicBool,
+) -> image_cache::Result<Vec<ScratchFrame>> {
+    let source = File::open(path)?;
+    match image::ImageFormat::from_path(path).ok() {
+        Some(image::ImageFormat::Gif) => {
+            let frames = image::gif::Decoder::new(source)?.into_frames();
+            decode_frames(frames, scratch_path, sender, stop)
+        }
+        Some(image::ImageFormat::Png) => {
+            let frames = image::png::Decoder::new(source)?.apng().into_frames();
+            decode_frames(frames, scratch_path, sender, stop)
+        }
+        other => Err(format!(
+            "in-file animation playback isn't implemented for {:?}",
+            other
+        )
+        .into()),
+    }
+}
+
+/// Shared frame-decoding loop for every `AnimationDecoder` backend (GIF,
+/// APNG): writes each frame's raw pixels to `scratch_path` and forwards it to
+/// `sender`, bailing out early (without treating it as an error) if playback
+/// is stopped before the source is fully decoded.
+fn decode_frames(
+    frames: image::Frames<'static>,
+    scratch_path: &Path,
+    sender: &SyncSender<image_cache::Result<AnimationFrame>>,
+    stop: &AtomicBool,
+) -> image_cache::Result<Vec<ScratchFrame>> {
+    let mut scratch = File::create(scratch_path)?;
+    let mut index = Vec::new();
+    let mut offset = 0u64;
+
+    for frame in frames {
+        if stop.load(Ordering::Relaxed) {
+            drop(scratch);
+            let _ = fs::remove_file(scratch_path);
+            return Ok(Vec::new());
+        }
+
+        let frame = frame?;
+        let delay_nanos = {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            (numer as u64) * 1_000_000 / (denom.max(1) as u64)
+        };
+        let buffer = frame.into_buffer();
+        let (width, height) = buffer.dimensions();
+        let pixels = buffer.into_raw();
+
+        scratch.write_all(&pixels)?;
+        index.push(ScratchFrame {
+            offset,
+            width,
+            height,
+            delay_nanos,
+        });
+        offset += pixels.len() as u64;
+
+        let should_stop = send_or_stop(
+            sender,
+            stop,
+            Ok(AnimationFrame {
+                pixels,
+                width,
+                height,
+                delay_nanos,
+            }),
+        );
+        if should_stop {
+            // Playback stopped before we even finished the first pass.
+            drop(scratch);
+            let _ = fs::remove_file(scratch_path);
+            return Ok(Vec::new());
+        }
+    }
+
+    Ok(index)
+}
@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+/// Below this remaining duration we stop trying to ask the OS for another
+/// nap and just spin-wait instead; requesting a sleep shorter than this is
+/// unreliable on most schedulers and tends to overshoot the deadline anyway.
+const SPIN_THRESHOLD: Duration = Duration::from_micros(750);
+
+/// Blocks the calling thread until `deadline`, aiming to come back as close
+/// to it as the platform allows.
+///
+/// On Linux this parks via `clock_nanosleep` against `CLOCK_MONOTONIC` using
+/// an absolute target time, which avoids the extra drift `std::thread::sleep`
+/// accumulates by re-deriving a relative duration every call. Elsewhere we
+/// fall back to `std::thread::sleep`. Either way, once we're within
+/// `SPIN_THRESHOLD` of the deadline we busy-spin instead of sleeping again,
+/// since an OS sleep that short is more likely to overshoot than to help.
+pub fn park_until(deadline: Instant) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+
+        let remaining = deadline - now;
+        if remaining <= SPIN_THRESHOLD {
+            while Instant::now() < deadline {
+                // Final sub-millisecond stretch: spin rather than sleep.
+            }
+            return;
+        }
+
+        platform::sleep(remaining - SPIN_THRESHOLD);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+    use std::mem;
+    use std::time::{Duration, Instant};
+
+    use libc::{clock_nanosleep, timespec, CLOCK_MONOTONIC, TIMER_ABSTIME};
+
+    /// Sleeps for roughly `duration`, computed as an absolute deadline against
+    /// `CLOCK_MONOTONIC` so the kernel (rather than our own elapsed-time math)
+    /// is responsible for waking us up at the right instant.
+    pub fn sleep(duration: Duration) {
+        let wake_at = Instant::now() + duration;
+        let target = match monotonic_now() {
+            Some(now) => add(now, duration),
+            // Couldn't read CLOCK_MONOTONIC directly; fall back to the
+            // std-library relative sleep rather than guessing.
+            None => {
+                ::std::thread::sleep(duration);
+                return;
+            }
+        };
+
+        loop {
+            let result =
+                unsafe { clock_nanosleep(CLOCK_MONOTONIC, TIMER_ABSTIME, &target, mem::zeroed()) };
+            if result == 0 || Instant::now() >= wake_at {
+                return;
+            }
+            // Interrupted by a signal; the syscall's contract is to retry
+            // with the same absolute target.
+            if result != libc::EINTR {
+                return;
+            }
+        }
+    }
+
+    fn monotonic_now() -> Option<timespec> {
+        let mut ts: timespec = unsafe { mem::zeroed() };
+        let result = unsafe { libc::clock_gettime(CLOCK_MONOTONIC, &mut ts) };
+        if result == 0 {
+            Some(ts)
+        } else {
+            let _ = io::Error::last_os_error();
+            None
+        }
+    }
+
+    fn add(base: timespec, duration: Duration) -> timespec {
+        let mut nanos = base.tv_nsec as i64 + duration.subsec_nanos() as i64;
+        let mut secs = base.tv_sec + duration.as_secs() as i64;
+        if nanos >= 1_000_000_000 {
+            nanos -= 1_000_000_000;
+            secs += 1;
+        }
+        timespec {
+            tv_sec: secs,
+            tv_nsec: nanos as _,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn ts(tv_sec: i64, tv_nsec: i64) -> timespec {
+            timespec {
+                tv_sec,
+                tv_nsec: tv_nsec as _,
+            }
+        }
+
+        #[test]
+        fn add_without_nanosecond_carry() {
+            let base = ts(10, 500_000_000);
+            let result = add(base, Duration::new(2, 100_000_000));
+            assert_eq!(result.tv_sec, 12);
+            assert_eq!(result.tv_nsec, 600_000_000);
+        }
+
+        #[test]
+        fn add_carries_a_full_second() {
+            let base = ts(10, 900_000_000);
+            let result = add(base, Duration::new(0, 200_000_000));
+            assert_eq!(result.tv_sec, 11);
+            assert_eq!(result.tv_nsec, 100_000_000);
+        }
+
+        #[test]
+        fn add_at_exactly_one_second_boundary_carries() {
+            // 999_999_999 + 1 == 1_000_000_000, which lands exactly on the
+            // boundary; the carry check is `>=`, so this does carry - pin the
+            // exact behaviour down explicitly.
+            let base = ts(5, 999_999_999);
+            let result = add(base, Duration::new(0, 1));
+            assert_eq!(result.tv_sec, 6);
+            assert_eq!(result.tv_nsec, 0);
+        }
+
+        #[test]
+        fn add_carries_across_multiple_whole_seconds() {
+            let base = ts(0, 0);
+            let result = add(base, Duration::new(3, 0));
+            assert_eq!(result.tv_sec, 3);
+            assert_eq!(result.tv_nsec, 0);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::time::Duration;
+
+    pub fn sleep(duration: Duration) {
+        ::std::thread::sleep(duration);
+    }
+}
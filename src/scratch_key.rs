@@ -0,0 +1,36 @@
+//! A small helper shared by the two on-disk scratch caches (the decoded-image
+//! spillover in `image_cache` and the in-file animation frame cache in
+//! `playback_manager::animation`): turning a source file into a scratch
+//! filename that won't collide with another source file of the same name
+//! elsewhere on disk, and that changes if the source file is replaced.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Hashes the *canonicalized* path (so two different directories containing
+/// a same-named file, e.g. two `thumbnail.gif`s, don't collide) together
+/// with the file's mtime (so a scratch entry is naturally invalidated if the
+/// file on disk changes underneath us).
+///
+/// Falls back to hashing the given path as-is if it can't be canonicalized
+/// or stat'd (e.g. it no longer exists) - still unique per input path, just
+/// without the collision/staleness guarantees canonicalizing gives us.
+pub fn hashed_name(path: &Path) -> String {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mtime = fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut hasher = DefaultHasher::new();
+    canonical.hash(&mut hasher);
+    mtime.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Convenience wrapper building a full scratch file path inside `dir`.
+pub fn scratch_path(dir: &Path, path: &Path, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", hashed_name(path), extension))
+}